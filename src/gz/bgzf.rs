@@ -0,0 +1,345 @@
+//! Random access into [BGZF](https://samtools.github.io/hts-specs/SAMv1.pdf)
+//! streams, the block-compressed gzip variant used throughout
+//! bioinformatics (BAM, tabix-indexed files, ...).
+//!
+//! A BGZF stream is an ordinary gzip multistream (see
+//! [`MultiGzDecoder`](super::bufread::MultiGzDecoder)) in which every member
+//! carries a two-byte `BC` subfield in its `FEXTRA` data giving the total
+//! length of that member minus one, and which always ends with a fixed 28-byte empty
+//! member marking EOF. Because each member is independently compressed,
+//! seeking to the start of a member plus an offset into its decompressed
+//! bytes is all that's needed to resume reading from any point without
+//! decompressing the whole stream from the start.
+//!
+//! [`BgzfReader`] understands this convention: it decodes one member at a
+//! time, stopping exactly at that member's trailer, and exposes the
+//! resulting position as a [`VirtualOffset`] that callers can stash and
+//! later feed back to [`BgzfReader::seek_virtual`].
+
+use std::cmp;
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+
+use super::bufread::GzDecoder;
+
+fn bad_block() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "not a valid BGZF block: missing or malformed `BC` extra subfield",
+    )
+}
+
+// `FEXTRA` is a sequence of subfields, each `SI1 SI2 SLEN(u16 LE) <SLEN
+// bytes>`; the gzip spec allows any number of them, and BGZF's `BC`
+// subfield (`SI1='B' SI2='C' SLEN=2 BSIZE`) need not be the only one or
+// come first. Scan past any others instead of requiring `BC` to be the
+// field's entire content.
+fn bsize_from_extra(extra: Option<&[u8]>) -> io::Result<u16> {
+    let mut rest = extra.ok_or_else(bad_block)?;
+    while let [si1, si2, lo, hi, tail @ ..] = rest {
+        let slen = (*lo as usize) | ((*hi as usize) << 8);
+        if tail.len() < slen {
+            break;
+        }
+        let (data, next) = tail.split_at(slen);
+        if *si1 == b'B' && *si2 == b'C' && slen == 2 {
+            return Ok((data[0] as u16) | ((data[1] as u16) << 8));
+        }
+        rest = next;
+    }
+    Err(bad_block())
+}
+
+// The BGZF spec (SAMv1 section 4.1.2) fixes the 28-byte empty member that
+// terminates a stream byte-for-byte; matching it exactly (rather than just
+// noticing a block decompressed to nothing) is what tells an EOF marker
+// apart from an ordinary member that happens to carry no data, e.g. a
+// flush with nothing pending.
+const BGZF_EOF_MARKER: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+// A compliant BGZF writer keeps every block's uncompressed size under
+// 64KiB so it fits the low 16 bits of a virtual offset; enforce that here
+// rather than silently wrapping `pos` into a bogus offset in `tell_virtual`.
+const MAX_BLOCK_LEN: usize = 1 << 16;
+
+/// A position within a BGZF stream: the compressed byte offset of a member
+/// packed into the high 48 bits, and the uncompressed byte offset within
+/// that member's decompressed data packed into the low 16 bits.
+///
+/// Virtual offsets are only meaningful relative to the BGZF stream that
+/// produced them; they are not ordinary byte offsets into either the
+/// compressed or the decompressed data.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VirtualOffset(u64);
+
+impl VirtualOffset {
+    /// Packs a compressed member offset and an offset into that member's
+    /// decompressed bytes into a single virtual offset.
+    pub fn new(compressed_offset: u64, uncompressed_offset: u16) -> VirtualOffset {
+        VirtualOffset((compressed_offset << 16) | (uncompressed_offset as u64))
+    }
+
+    /// Builds a virtual offset from its packed 64-bit representation, e.g.
+    /// one read back from an on-disk index.
+    pub fn from_raw(raw: u64) -> VirtualOffset {
+        VirtualOffset(raw)
+    }
+
+    /// Returns the packed 64-bit representation of this virtual offset.
+    pub fn into_raw(self) -> u64 {
+        self.0
+    }
+
+    /// The compressed byte offset, within the BGZF stream, of the member
+    /// this virtual offset points into.
+    pub fn compressed_offset(self) -> u64 {
+        self.0 >> 16
+    }
+
+    /// The uncompressed byte offset within that member's decompressed data.
+    pub fn uncompressed_offset(self) -> u16 {
+        (self.0 & 0xffff) as u16
+    }
+}
+
+/// A reader over a BGZF stream that supports seeking to a [`VirtualOffset`]
+/// without decompressing everything before it.
+///
+/// `BgzfReader` decodes one member at a time using the same header parsing
+/// [`GzDecoder`] relies on, validating that each member's `FEXTRA` carries a
+/// well-formed `BC` subfield along the way.
+#[derive(Debug)]
+pub struct BgzfReader<R> {
+    inner: R,
+    block_start: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    at_eof_marker: bool,
+}
+
+impl<R: BufRead + Seek> BgzfReader<R> {
+    /// Creates a new reader, decoding the first block at the current
+    /// position of `inner`.
+    pub fn new(mut inner: R) -> io::Result<BgzfReader<R>> {
+        let block_start = inner.seek(SeekFrom::Current(0))?;
+        let mut r = BgzfReader {
+            inner,
+            block_start,
+            buf: Vec::new(),
+            pos: 0,
+            at_eof_marker: false,
+        };
+        r.load_block()?;
+        Ok(r)
+    }
+
+    // Decodes the single member starting at `self.block_start`, leaving
+    // `inner` positioned exactly at the start of the next member.
+    fn load_block(&mut self) -> io::Result<()> {
+        self.buf.clear();
+        self.pos = 0;
+        self.at_eof_marker = false;
+
+        let header = {
+            let mut dec = GzDecoder::new(&mut self.inner);
+            dec.read_to_end(&mut self.buf)?;
+            dec.header().cloned().ok_or_else(bad_block)?
+        };
+        let bsize = bsize_from_extra(header.extra())?;
+
+        let block_end = self.inner.seek(SeekFrom::Current(0))?;
+        let block_len = block_end - self.block_start;
+        if block_len != bsize as u64 + 1 {
+            return Err(bad_block());
+        }
+        if self.buf.len() >= MAX_BLOCK_LEN {
+            return Err(bad_block());
+        }
+
+        if self.buf.is_empty() && block_len == BGZF_EOF_MARKER.len() as u64 {
+            let mut raw = [0u8; BGZF_EOF_MARKER.len()];
+            self.inner.seek(SeekFrom::Start(self.block_start))?;
+            self.inner.read_exact(&mut raw)?;
+            self.inner.seek(SeekFrom::Start(block_end))?;
+            self.at_eof_marker = raw == BGZF_EOF_MARKER;
+        }
+        Ok(())
+    }
+
+    fn load_block_at(&mut self, compressed_offset: u64) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(compressed_offset))?;
+        self.block_start = compressed_offset;
+        self.load_block()
+    }
+
+    /// Seeks to `voffset`, decoding the member it points into if it isn't
+    /// the one already loaded.
+    pub fn seek_virtual(&mut self, voffset: VirtualOffset) -> io::Result<()> {
+        if voffset.compressed_offset() != self.block_start {
+            self.load_block_at(voffset.compressed_offset())?;
+        }
+        let within = voffset.uncompressed_offset() as usize;
+        if within > self.buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "virtual offset points past the end of its BGZF block",
+            ));
+        }
+        self.pos = within;
+        Ok(())
+    }
+
+    /// Returns the virtual offset of the next byte `read` will return.
+    pub fn tell_virtual(&self) -> VirtualOffset {
+        VirtualOffset::new(self.block_start, self.pos as u16)
+    }
+
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying reader.
+    ///
+    /// Note that mutation of the reader may result in surprising results if
+    /// this reader is continued to be used.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Consumes this reader, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: BufRead + Seek> Read for BgzfReader<R> {
+    fn read(&mut self, into: &mut [u8]) -> io::Result<usize> {
+        // A legal member can decompress to zero bytes (e.g. a flush with
+        // nothing pending), so keep advancing until we hit one with data
+        // or the actual EOF marker rather than stopping at the first empty
+        // block.
+        while self.pos >= self.buf.len() {
+            let next = self.inner.seek(SeekFrom::Current(0))?;
+            self.load_block_at(next)?;
+            if self.at_eof_marker {
+                return Ok(0);
+            }
+        }
+
+        let avail = &self.buf[self.pos..];
+        let n = cmp::min(into.len(), avail.len());
+        into[..n].copy_from_slice(&avail[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::FEXTRA;
+    use crc::Crc;
+    use std::io::Cursor;
+
+    fn push_u16_le(v: &mut Vec<u8>, val: u16) {
+        v.push((val & 0xff) as u8);
+        v.push((val >> 8) as u8);
+    }
+
+    fn push_u32_le(v: &mut Vec<u8>, val: u32) {
+        v.push(val as u8);
+        v.push((val >> 8) as u8);
+        v.push((val >> 16) as u8);
+        v.push((val >> 24) as u8);
+    }
+
+    // Builds a single BGZF member wrapping `data`, using a stored (raw)
+    // deflate block so the test doesn't depend on a particular compressor.
+    fn bgzf_member(data: &[u8]) -> Vec<u8> {
+        let mut deflate = vec![1]; // BFINAL=1, BTYPE=00 (stored)
+        push_u16_le(&mut deflate, data.len() as u16);
+        push_u16_le(&mut deflate, !(data.len() as u16));
+        deflate.extend_from_slice(data);
+
+        let mut crc = Crc::new();
+        crc.update(data);
+
+        let mut member = vec![0x1f, 0x8b, 8, FEXTRA, 0, 0, 0, 0, 0, 0xff];
+        push_u16_le(&mut member, 6); // XLEN
+        member.extend_from_slice(b"BC");
+        push_u16_le(&mut member, 2); // SLEN
+        let bsize_pos = member.len();
+        push_u16_le(&mut member, 0); // BSIZE, patched in below
+
+        member.extend_from_slice(&deflate);
+        push_u32_le(&mut member, crc.sum());
+        push_u32_le(&mut member, data.len() as u32);
+
+        let bsize = (member.len() - 1) as u16;
+        member[bsize_pos] = (bsize & 0xff) as u8;
+        member[bsize_pos + 1] = (bsize >> 8) as u8;
+        member
+    }
+
+    #[test]
+    fn seek_virtual_round_trips() {
+        let first = bgzf_member(b"hello ");
+        let second = bgzf_member(b"world!");
+        let first_len = first.len() as u64;
+
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&first);
+        stream.extend_from_slice(&second);
+        stream.extend_from_slice(&BGZF_EOF_MARKER);
+
+        let mut reader = BgzfReader::new(Cursor::new(stream)).unwrap();
+        let mut buf = [0u8; 6];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello ");
+
+        // Seek into the middle of the second block and read the rest of it.
+        let voffset = VirtualOffset::new(first_len, 3);
+        reader.seek_virtual(voffset).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"ld!");
+
+        // Past the last byte of data, tell_virtual reports the EOF marker.
+        assert_eq!(
+            reader.tell_virtual().compressed_offset(),
+            first_len + second.len() as u64
+        );
+    }
+
+    #[test]
+    fn bsize_from_extra_scans_past_other_subfields() {
+        let mut extra = Vec::new();
+        // An unrelated subfield ahead of BC should be skipped, not rejected.
+        extra.push(b'Z');
+        extra.push(b'Z');
+        push_u16_le(&mut extra, 3);
+        extra.extend_from_slice(&[1, 2, 3]);
+        extra.push(b'B');
+        extra.push(b'C');
+        push_u16_le(&mut extra, 2);
+        push_u16_le(&mut extra, 1234);
+
+        assert_eq!(bsize_from_extra(Some(&extra)).unwrap(), 1234);
+    }
+
+    #[test]
+    fn missing_eof_marker_is_an_error_not_a_permanent_would_block() {
+        // A BGZF stream that's missing its trailing 28-byte EOF marker
+        // (e.g. a partially-written BAM/tabix file) must surface as a
+        // real error once the last real block is drained, not hang
+        // behind a WouldBlock that a synchronous caller can't retry past.
+        let only_member = bgzf_member(b"hi");
+        let mut reader = BgzfReader::new(Cursor::new(only_member)).unwrap();
+        let mut buf = Vec::new();
+        let err = reader.read_to_end(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -9,8 +9,8 @@ use futures::Poll;
 use tokio_io::{AsyncRead, AsyncWrite};
 
 use super::{GzBuilder, GzHeader};
-use super::{FCOMMENT, FEXTRA, FHCRC, FNAME};
-use crc::CrcReader;
+use super::{FCOMMENT, FEXTRA, FHCRC, FNAME, FTEXT};
+use crc::{Crc, CrcReader};
 use deflate;
 use Compression;
 
@@ -34,87 +34,254 @@ fn bad_header() -> io::Error {
     io::Error::new(io::ErrorKind::InvalidInput, "invalid gzip header")
 }
 
-fn read_le_u16<R: Read>(r: &mut R) -> io::Result<u16> {
-    let mut b = [0; 2];
-    r.read_exact(&mut b)?;
-    Ok((b[0] as u16) | ((b[1] as u16) << 8))
+/// Default cap, in bytes, on the total size of each of the header's
+/// filename, comment and extra fields. 65535 is the field's natural
+/// maximum: `FEXTRA`'s length prefix is a `u16`, and this keeps
+/// NUL-terminated fields from growing without bound on a crafted stream
+/// that never supplies the terminator.
+pub(crate) const MAX_HEADER_BUF: usize = 65535;
+
+// Reads into `buf[*pos..]`. Returns `Ok(true)` once `buf` is full, or
+// `Ok(false)` if `r` would block before that happened; `*pos` tracks how
+// far along `buf` we got so the next call can resume exactly there. A
+// genuine `Ok(0)` means `r` has permanently run out of bytes mid-header,
+// which is not something a caller can usefully retry, so that's reported
+// as `bad_header()` rather than folded into the same `Ok(false)` a
+// transient `WouldBlock` gets.
+fn fill<R: Read>(r: &mut R, buf: &mut [u8], pos: &mut usize) -> io::Result<bool> {
+    while *pos < buf.len() {
+        match r.read(&mut buf[*pos..]) {
+            Ok(0) => return Err(bad_header()),
+            Ok(n) => *pos += n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
 }
 
-pub(crate) fn read_gz_header<R: Read>(r: &mut R) -> io::Result<GzHeader> {
-    let mut crc_reader = CrcReader::new(r);
-    let mut header = [0; 10];
-    crc_reader.read_exact(&mut header)?;
-
-    let id1 = header[0];
-    let id2 = header[1];
-    if id1 != 0x1f || id2 != 0x8b {
-        return Err(bad_header());
-    }
-    let cm = header[2];
-    if cm != 8 {
-        return Err(bad_header());
-    }
-
-    let flg = header[3];
-    let mtime = ((header[4] as u32) << 0)
-        | ((header[5] as u32) << 8)
-        | ((header[6] as u32) << 16)
-        | ((header[7] as u32) << 24);
-    let _xfl = header[8];
-    let os = header[9];
-
-    let extra = if flg & FEXTRA != 0 {
-        let xlen = read_le_u16(&mut crc_reader)?;
-        let mut extra = vec![0; xlen as usize];
-        crc_reader.read_exact(&mut extra)?;
-        Some(extra)
-    } else {
-        None
-    };
-    let filename = if flg & FNAME != 0 {
-        // wow this is slow
-        let mut b = Vec::new();
-        for byte in crc_reader.by_ref().bytes() {
-            let byte = byte?;
-            if byte == 0 {
-                break;
-            }
-            b.push(byte);
+// Like `fill`, but folds the newly read bytes into `crc` as it goes.
+fn fill_crc<R: Read>(crc: &mut Crc, r: &mut R, buf: &mut [u8], pos: &mut usize) -> io::Result<bool> {
+    let before = *pos;
+    let done = fill(r, buf, pos)?;
+    crc.update(&buf[before..*pos]);
+    Ok(done)
+}
+
+// Like `fill_crc`, but for a field with no fixed length: consumes bytes up
+// to and including the next NUL, appending everything before it to `buf`.
+// Uses the reader's own buffer to scan for the terminator in bulk instead
+// of pulling bytes through one at a time. Bails out with `bad_header()`
+// rather than growing `buf` past `max_len`, so a stream that never supplies
+// the terminator can't force unbounded memory growth. A `fill_buf` that
+// comes back empty means `r` has permanently run out of bytes before the
+// terminator showed up, which gets the same `bad_header()` treatment as
+// exceeding `max_len` -- it's `WouldBlock` alone that's transient here.
+fn read_cstr_crc<R: BufRead>(
+    crc: &mut Crc,
+    r: &mut R,
+    buf: &mut Vec<u8>,
+    max_len: usize,
+) -> io::Result<bool> {
+    loop {
+        let avail = match r.fill_buf() {
+            Ok(avail) if avail.is_empty() => return Err(bad_header()),
+            Ok(avail) => avail,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        let (done, added, consumed) = match avail.iter().position(|&b| b == 0) {
+            Some(i) => (true, i, i + 1),
+            None => (false, avail.len(), avail.len()),
+        };
+        if buf.len() + added > max_len {
+            return Err(bad_header());
         }
-        Some(b)
-    } else {
-        None
-    };
-    let comment = if flg & FCOMMENT != 0 {
-        // wow this is slow
-        let mut b = Vec::new();
-        for byte in crc_reader.by_ref().bytes() {
-            let byte = byte?;
-            if byte == 0 {
-                break;
-            }
-            b.push(byte);
+
+        crc.update(&avail[..consumed]);
+        buf.extend_from_slice(&avail[..added]);
+        r.consume(consumed);
+        if done {
+            return Ok(true);
         }
-        Some(b)
-    } else {
-        None
-    };
-
-    if flg & FHCRC != 0 {
-        let calced_crc = crc_reader.crc().sum() as u16;
-        let stored_crc = read_le_u16(&mut crc_reader)?;
-        if calced_crc != stored_crc {
-            return Err(corrupt());
+    }
+}
+
+#[derive(Debug)]
+enum GzHeaderState {
+    Fixed,
+    Xlen,
+    Extra,
+    Filename,
+    Comment,
+    Crc,
+    Done,
+}
+
+/// A resumable parser for a single gzip member header.
+///
+/// Unlike the byte-at-a-time loop this replaces, `GzHeaderParser` keeps just
+/// enough state — which field it's currently on, how many bytes of that
+/// field remain, the partial filename/comment buffers and the running
+/// header CRC — to pick up exactly where it left off. Callers feed it bytes
+/// through repeated calls to [`parse`](GzHeaderParser::parse); a
+/// `WouldBlock` or a short read part-way through a field no longer throws
+/// away the bytes that were already consumed.
+#[derive(Debug)]
+pub(crate) struct GzHeaderParser {
+    state: GzHeaderState,
+    crc: Crc,
+    flg: u8,
+    mtime: u32,
+    os: u8,
+    xlen: u16,
+    fixed_buf: [u8; 10],
+    fixed_pos: usize,
+    xlen_buf: [u8; 2],
+    xlen_pos: usize,
+    extra: Option<Vec<u8>>,
+    extra_pos: usize,
+    filename: Option<Vec<u8>>,
+    comment: Option<Vec<u8>>,
+    crc_buf: [u8; 2],
+    crc_pos: usize,
+    max_len: usize,
+}
+
+impl GzHeaderParser {
+    /// Caps the total bytes accumulated for the filename, comment and extra
+    /// fields at `max_len`; pass `MAX_HEADER_BUF` for the default limit.
+    pub(crate) fn with_max_len(max_len: usize) -> GzHeaderParser {
+        GzHeaderParser {
+            state: GzHeaderState::Fixed,
+            crc: Crc::new(),
+            flg: 0,
+            mtime: 0,
+            os: 0,
+            xlen: 0,
+            fixed_buf: [0; 10],
+            fixed_pos: 0,
+            xlen_buf: [0; 2],
+            xlen_pos: 0,
+            extra: None,
+            extra_pos: 0,
+            filename: None,
+            comment: None,
+            crc_buf: [0; 2],
+            crc_pos: 0,
+            max_len,
         }
     }
 
-    Ok(GzHeader {
-        extra: extra,
-        filename: filename,
-        comment: comment,
-        operating_system: os,
-        mtime: mtime,
-    })
+    /// Feeds bytes from `r` into the parser.
+    ///
+    /// Returns `Ok(None)` if `r` returned `WouldBlock` before the header was
+    /// fully parsed — the caller should come back with more data and call
+    /// `parse` again, which resumes from the exact field and byte offset
+    /// it stopped at. If `r` reaches the end of its input mid-header, an
+    /// error is returned instead. Returns `Ok(Some(header))` once the header
+    /// (and its optional CRC-16) has been read and validated in full.
+    pub(crate) fn parse<R: BufRead>(&mut self, r: &mut R) -> io::Result<Option<GzHeader>> {
+        loop {
+            match self.state {
+                GzHeaderState::Fixed => {
+                    if !fill_crc(&mut self.crc, r, &mut self.fixed_buf, &mut self.fixed_pos)? {
+                        return Ok(None);
+                    }
+                    if self.fixed_buf[0] != 0x1f || self.fixed_buf[1] != 0x8b {
+                        return Err(bad_header());
+                    }
+                    if self.fixed_buf[2] != 8 {
+                        return Err(bad_header());
+                    }
+                    self.flg = self.fixed_buf[3];
+                    self.mtime = ((self.fixed_buf[4] as u32) << 0)
+                        | ((self.fixed_buf[5] as u32) << 8)
+                        | ((self.fixed_buf[6] as u32) << 16)
+                        | ((self.fixed_buf[7] as u32) << 24);
+                    self.os = self.fixed_buf[9];
+                    self.state = GzHeaderState::Xlen;
+                }
+                GzHeaderState::Xlen => {
+                    if self.flg & FEXTRA == 0 {
+                        self.state = GzHeaderState::Filename;
+                        continue;
+                    }
+                    if !fill_crc(&mut self.crc, r, &mut self.xlen_buf, &mut self.xlen_pos)? {
+                        return Ok(None);
+                    }
+                    self.xlen = (self.xlen_buf[0] as u16) | ((self.xlen_buf[1] as u16) << 8);
+                    if self.xlen as usize > self.max_len {
+                        return Err(bad_header());
+                    }
+                    self.extra = Some(vec![0; self.xlen as usize]);
+                    self.extra_pos = 0;
+                    self.state = GzHeaderState::Extra;
+                }
+                GzHeaderState::Extra => {
+                    let done = {
+                        let extra = self.extra.as_mut().unwrap();
+                        fill_crc(&mut self.crc, r, extra, &mut self.extra_pos)?
+                    };
+                    if !done {
+                        return Ok(None);
+                    }
+                    self.state = GzHeaderState::Filename;
+                }
+                GzHeaderState::Filename => {
+                    if self.flg & FNAME == 0 {
+                        self.state = GzHeaderState::Comment;
+                        continue;
+                    }
+                    let buf = self.filename.get_or_insert_with(Vec::new);
+                    if !read_cstr_crc(&mut self.crc, r, buf, self.max_len)? {
+                        return Ok(None);
+                    }
+                    self.state = GzHeaderState::Comment;
+                }
+                GzHeaderState::Comment => {
+                    if self.flg & FCOMMENT == 0 {
+                        self.state = GzHeaderState::Crc;
+                        continue;
+                    }
+                    let buf = self.comment.get_or_insert_with(Vec::new);
+                    if !read_cstr_crc(&mut self.crc, r, buf, self.max_len)? {
+                        return Ok(None);
+                    }
+                    self.state = GzHeaderState::Crc;
+                }
+                GzHeaderState::Crc => {
+                    if self.flg & FHCRC != 0 {
+                        // Snapshot the header CRC before the stored CRC-16
+                        // bytes themselves get read (they aren't part of
+                        // the checksum they describe).
+                        let calced_crc = self.crc.sum() as u16;
+                        if !fill(r, &mut self.crc_buf, &mut self.crc_pos)? {
+                            return Ok(None);
+                        }
+                        let stored_crc =
+                            (self.crc_buf[0] as u16) | ((self.crc_buf[1] as u16) << 8);
+                        if calced_crc != stored_crc {
+                            return Err(corrupt());
+                        }
+                    }
+                    self.state = GzHeaderState::Done;
+                }
+                GzHeaderState::Done => {
+                    return Ok(Some(GzHeader {
+                        extra: self.extra.take(),
+                        filename: self.filename.take(),
+                        comment: self.comment.take(),
+                        operating_system: self.os,
+                        extra_flags: self.fixed_buf[8],
+                        is_text: self.flg & FTEXT != 0,
+                        mtime: self.mtime,
+                    }));
+                }
+            }
+        }
+    }
 }
 
 /// A gzip streaming encoder
@@ -301,67 +468,41 @@ pub struct GzDecoder<R> {
     inner: GzState,
     header: Option<GzHeader>,
     reader: CrcReader<deflate::bufread::DeflateDecoder<R>>,
-    multi: bool
+    multi: bool,
+    max_header_len: usize,
 }
 
 #[derive(Debug)]
 enum GzState {
-    Header(Vec<u8>),
+    Header(GzHeaderParser),
     Body,
     Finished(usize, [u8; 8]),
     Err(io::Error),
     End
 }
 
-struct Buffer<'a, T> {
-    buf: io::Take<io::Cursor<&'a mut Vec<u8>>>,
-    reader: &'a mut T
-}
-
-impl<'a, T> Buffer<'a, T> {
-    fn new(buf: &'a mut Vec<u8>, reader: &'a mut T) -> Buffer<'a, T> {
-        let len = buf.len();
-        Buffer { buf: io::Cursor::new(buf).take(len as _), reader }
-    }
-}
-
-impl<'a, T: Read> Read for Buffer<'a, T> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut len = self.buf.read(buf)?;
-        if buf.len() > len {
-            match self.reader.read(&mut buf[len..])? {
-                // eof
-                0 => return Err(bad_header()),
-                len2 => {
-                    self.buf.get_mut().get_mut().extend_from_slice(&buf[len..][..len2]);
-                    len += len2;
-                }
-            }
-        }
-        Ok(len)
-    }
-}
-
 impl<R: BufRead> GzDecoder<R> {
     /// Creates a new decoder from the given reader, immediately parsing the
     /// gzip header.
-    pub fn new(mut r: R) -> GzDecoder<R> {
-        let mut buf = Vec::new();
-        let mut header = None;
+    pub fn new(r: R) -> GzDecoder<R> {
+        GzDecoder::new_with_max_header_len(r, MAX_HEADER_BUF)
+    }
 
-        let result = {
-            let mut reader = Buffer::new(&mut buf, &mut r);
-            read_gz_header(&mut reader)
-        };
+    /// Like [`new`](GzDecoder::new), but caps the total bytes accumulated
+    /// for each of the header's filename, comment and extra fields at
+    /// `max_header_len` instead of the default 65535. Embedders parsing
+    /// untrusted gzip streams can pass a smaller value to bound memory use
+    /// more tightly.
+    pub fn new_with_max_header_len(mut r: R, max_header_len: usize) -> GzDecoder<R> {
+        let mut header = None;
+        let mut parser = GzHeaderParser::with_max_len(max_header_len);
 
-        let state = match result {
-            Ok(hdr) => {
+        let state = match parser.parse(&mut r) {
+            Ok(Some(hdr)) => {
                 header = Some(hdr);
                 GzState::Body
             },
-            Err(ref err) if io::ErrorKind::WouldBlock == err.kind()
-                || io::ErrorKind::UnexpectedEof == err.kind()
-                => GzState::Header(buf),
+            Ok(None) => GzState::Header(parser),
             Err(err) => GzState::Err(err)
         };
 
@@ -369,6 +510,7 @@ impl<R: BufRead> GzDecoder<R> {
             inner: state,
             reader: CrcReader::new(deflate::bufread::DeflateDecoder::new(r)),
             multi: false,
+            max_header_len,
             header
         }
     }
@@ -406,7 +548,7 @@ impl<R> GzDecoder<R> {
 
 impl<R: BufRead> Read for GzDecoder<R> {
     fn read(&mut self, into: &mut [u8]) -> io::Result<usize> {
-        let GzDecoder { inner, header, reader, multi } = self;
+        let GzDecoder { inner, header, reader, multi, max_header_len } = self;
 
         enum Next {
             None,
@@ -421,16 +563,13 @@ impl<R: BufRead> Read for GzDecoder<R> {
 
         loop {
             match inner {
-                GzState::Header(buf) => {
-                    let mut reader = Buffer::new(buf, reader.get_mut().get_mut());
-                    match read_gz_header(&mut reader) {
-                        Ok(hdr) => {
+                GzState::Header(parser) => {
+                    match parser.parse(reader.get_mut().get_mut()) {
+                        Ok(Some(hdr)) => {
                             *header = Some(hdr);
                             next = Next::Body;
                         },
-                        Err(ref err) if io::ErrorKind::WouldBlock == err.kind()
-                            || io::ErrorKind::UnexpectedEof == err.kind()
-                            => return Err(io::ErrorKind::WouldBlock.into()),
+                        Ok(None) => return Err(io::ErrorKind::WouldBlock.into()),
                         Err(err) => next = Next::Err(err)
                     }
                 },
@@ -488,7 +627,7 @@ impl<R: BufRead> Read for GzDecoder<R> {
                     reader.reset();
                     reader.get_mut().reset_data();
                     header.take();
-                    *inner = GzState::Header(Vec::new());
+                    *inner = GzState::Header(GzHeaderParser::with_max_len(*max_header_len));
                 },
                 Next::Body => *inner = GzState::Body,
                 Next::Finished => *inner = GzState::Finished(0, [0; 8]),
@@ -572,6 +711,16 @@ impl<R: BufRead> MultiGzDecoder<R> {
     pub fn new(r: R) -> MultiGzDecoder<R> {
         MultiGzDecoder(GzDecoder::new(r).multi(true))
     }
+
+    /// Like [`new`](MultiGzDecoder::new), but caps the total bytes
+    /// accumulated for each member header's filename, comment and extra
+    /// fields at `max_header_len` instead of the default 65535. Since a
+    /// multistream can chain arbitrarily many header-only members,
+    /// embedders parsing untrusted gzip can use this to tighten the bound
+    /// once up front for the whole stream.
+    pub fn new_with_max_header_len(r: R, max_header_len: usize) -> MultiGzDecoder<R> {
+        MultiGzDecoder(GzDecoder::new_with_max_header_len(r, max_header_len).multi(true))
+    }
 }
 
 impl<R> MultiGzDecoder<R> {
@@ -624,3 +773,123 @@ impl<R: AsyncWrite + BufRead> AsyncWrite for MultiGzDecoder<R> {
         self.get_mut().shutdown()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A reader that returns `WouldBlock` exactly once, the first time it's
+    // asked to read or fill its buffer at or past `block_before`, then
+    // behaves like an ordinary reader over `data` from then on.
+    struct FlakyReader {
+        data: Vec<u8>,
+        pos: usize,
+        block_before: usize,
+        blocked: bool,
+    }
+
+    impl FlakyReader {
+        fn maybe_block(&mut self) -> io::Result<()> {
+            if !self.blocked && self.pos >= self.block_before {
+                self.blocked = true;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            Ok(())
+        }
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.maybe_block()?;
+            let cap = if self.blocked {
+                self.data.len()
+            } else {
+                cmp::min(self.data.len(), self.block_before)
+            };
+            let avail = &self.data[self.pos..cap];
+            let n = cmp::min(buf.len(), avail.len());
+            buf[..n].copy_from_slice(&avail[..n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    impl BufRead for FlakyReader {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            self.maybe_block()?;
+            Ok(&self.data[self.pos..])
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.pos += amt;
+        }
+    }
+
+    #[test]
+    fn parser_resumes_after_would_block() {
+        let mut r = FlakyReader {
+            data: vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff],
+            pos: 0,
+            block_before: 4,
+            blocked: false,
+        };
+        let mut parser = GzHeaderParser::with_max_len(MAX_HEADER_BUF);
+
+        assert!(parser.parse(&mut r).unwrap().is_none());
+        assert_eq!(r.pos, 4);
+
+        let header = parser
+            .parse(&mut r)
+            .unwrap()
+            .expect("header should be complete once unblocked");
+        assert_eq!(header.operating_system(), super::super::OperatingSystem::Unknown);
+    }
+
+    #[test]
+    fn oversized_filename_is_rejected() {
+        let mut data = vec![0x1f, 0x8b, 8, FNAME, 0, 0, 0, 0, 0, 0xff];
+        data.extend(std::iter::repeat(b'a').take(10));
+        let mut r = io::Cursor::new(data);
+
+        let mut parser = GzHeaderParser::with_max_len(4);
+        let err = parser.parse(&mut r).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn oversized_comment_is_rejected() {
+        let mut data = vec![0x1f, 0x8b, 8, FCOMMENT, 0, 0, 0, 0, 0, 0xff];
+        data.extend(std::iter::repeat(b'a').take(10));
+        let mut r = io::Cursor::new(data);
+
+        let mut parser = GzHeaderParser::with_max_len(4);
+        let err = parser.parse(&mut r).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn oversized_extra_is_rejected() {
+        let mut data = vec![0x1f, 0x8b, 8, FEXTRA, 0, 0, 0, 0, 0, 0xff];
+        data.push(10);
+        data.push(0);
+        data.extend(std::iter::repeat(0u8).take(10));
+        let mut r = io::Cursor::new(data);
+
+        let mut parser = GzHeaderParser::with_max_len(4);
+        let err = parser.parse(&mut r).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn truncated_header_is_an_error_not_a_permanent_would_block() {
+        // A genuine Ok(0) from an ordinary reader (no WouldBlock involved)
+        // means the stream ran out of bytes for good, so it must surface
+        // as an error rather than be treated the same as a transient
+        // WouldBlock that a caller could retry past.
+        let data = vec![0x1f, 0x8b, 8, 0, 0, 0];
+        let mut gz = GzDecoder::new(io::Cursor::new(data));
+        let mut out = Vec::new();
+        let err = gz.read_to_end(&mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,291 @@
+use std::cmp;
+use std::ffi::CString;
+use std::io::BufRead;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use Compression;
+
+pub(crate) const FTEXT: u8 = 1;
+pub(crate) const FHCRC: u8 = 2;
+pub(crate) const FEXTRA: u8 = 4;
+pub(crate) const FNAME: u8 = 8;
+pub(crate) const FCOMMENT: u8 = 16;
+
+pub mod bgzf;
+pub mod bufread;
+
+/// The operating system on which a gzip member was compressed, as recorded
+/// in the header's single OS byte (RFC 1952, section 2.3.1.2).
+///
+/// `Unknown` stands for the reserved value 255 as well as any value not
+/// assigned a meaning by the RFC.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OperatingSystem {
+    Fat,
+    Amiga,
+    Vms,
+    Unix,
+    VmCms,
+    AtariTos,
+    Hpfs,
+    Macintosh,
+    ZSystem,
+    CpM,
+    Tops20,
+    Ntfs,
+    Qdos,
+    Acorn,
+    Unknown,
+}
+
+impl OperatingSystem {
+    fn from_u8(os: u8) -> OperatingSystem {
+        match os {
+            0 => OperatingSystem::Fat,
+            1 => OperatingSystem::Amiga,
+            2 => OperatingSystem::Vms,
+            3 => OperatingSystem::Unix,
+            4 => OperatingSystem::VmCms,
+            5 => OperatingSystem::AtariTos,
+            6 => OperatingSystem::Hpfs,
+            7 => OperatingSystem::Macintosh,
+            8 => OperatingSystem::ZSystem,
+            9 => OperatingSystem::CpM,
+            10 => OperatingSystem::Tops20,
+            11 => OperatingSystem::Ntfs,
+            12 => OperatingSystem::Qdos,
+            13 => OperatingSystem::Acorn,
+            _ => OperatingSystem::Unknown,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            OperatingSystem::Fat => 0,
+            OperatingSystem::Amiga => 1,
+            OperatingSystem::Vms => 2,
+            OperatingSystem::Unix => 3,
+            OperatingSystem::VmCms => 4,
+            OperatingSystem::AtariTos => 5,
+            OperatingSystem::Hpfs => 6,
+            OperatingSystem::Macintosh => 7,
+            OperatingSystem::ZSystem => 8,
+            OperatingSystem::CpM => 9,
+            OperatingSystem::Tops20 => 10,
+            OperatingSystem::Ntfs => 11,
+            OperatingSystem::Qdos => 12,
+            OperatingSystem::Acorn => 13,
+            OperatingSystem::Unknown => 255,
+        }
+    }
+}
+
+/// A structure representing the header of a gzip stream.
+///
+/// The header can contain metadata about the file that was compressed, if
+/// present.
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct GzHeader {
+    extra: Option<Vec<u8>>,
+    filename: Option<Vec<u8>>,
+    comment: Option<Vec<u8>>,
+    operating_system: u8,
+    extra_flags: u8,
+    is_text: bool,
+    mtime: u32,
+}
+
+impl GzHeader {
+    /// Returns the `filename` field of this gzip stream's header, if present.
+    pub fn filename(&self) -> Option<&[u8]> {
+        self.filename.as_ref().map(|s| &s[..])
+    }
+
+    /// Returns the `extra` field of this gzip stream's header, if present.
+    pub fn extra(&self) -> Option<&[u8]> {
+        self.extra.as_ref().map(|s| &s[..])
+    }
+
+    /// Returns the `comment` field of this gzip stream's header, if present.
+    pub fn comment(&self) -> Option<&[u8]> {
+        self.comment.as_ref().map(|s| &s[..])
+    }
+
+    /// Returns the operating system on which this gzip stream was
+    /// compressed, decoded from the header's OS byte.
+    pub fn operating_system(&self) -> OperatingSystem {
+        OperatingSystem::from_u8(self.operating_system)
+    }
+
+    /// Returns the header's XFL byte, a compressor-specific hint (for the
+    /// deflate method, 2 means "compressor used maximum compression" and 4
+    /// means "compressor used fastest algorithm").
+    pub fn extra_flags(&self) -> u8 {
+        self.extra_flags
+    }
+
+    /// Returns whether the FTEXT flag is set, i.e. the compressor believed
+    /// the uncompressed data to be ASCII text.
+    pub fn is_text(&self) -> bool {
+        self.is_text
+    }
+
+    /// This gives the most recent modification time of the original file being compressed.
+    ///
+    /// The time is in Unix format, i.e., seconds since 00:00:00 GMT, Jan. 1, 1970.
+    /// (Note that this may cause problems for MS-DOS and other systems that use local
+    /// rather than Universal time.) If the compressed data did not come from a file,
+    /// `mtime` is set to the time at which compression started.
+    /// `mtime` = 0 means no time stamp is available.
+    ///
+    /// The usage of `mtime` is discouraged because of Year 2038 problem.
+    pub fn mtime(&self) -> u32 {
+        self.mtime
+    }
+
+    /// Returns the `mtime` field of this gzip stream's header as a
+    /// `SystemTime`, or `None` if the header's `mtime` is 0 (i.e. no
+    /// timestamp is available).
+    pub fn mtime_as_datetime(&self) -> Option<SystemTime> {
+        if self.mtime == 0 {
+            None
+        } else {
+            Some(UNIX_EPOCH + Duration::from_secs(self.mtime as u64))
+        }
+    }
+}
+
+/// A builder structure to create a new gzip Encoder.
+///
+/// This structure controls header configuration options such as the
+/// filename.
+#[derive(Debug)]
+pub struct GzBuilder {
+    extra: Option<Vec<u8>>,
+    filename: Option<CString>,
+    comment: Option<CString>,
+    operating_system: Option<OperatingSystem>,
+    is_text: bool,
+    mtime: u32,
+}
+
+impl GzBuilder {
+    /// Create a new blank builder with no header by default.
+    pub fn new() -> GzBuilder {
+        GzBuilder {
+            extra: None,
+            filename: None,
+            comment: None,
+            operating_system: None,
+            is_text: false,
+            mtime: 0,
+        }
+    }
+
+    /// Configure the `mtime` field in the header.
+    pub fn mtime(mut self, mtime: u32) -> GzBuilder {
+        self.mtime = mtime;
+        self
+    }
+
+    /// Like `mtime`, but takes a `SystemTime` and truncates it to whole
+    /// seconds since the Unix epoch instead of a raw `u32`. A time before
+    /// the epoch is stamped as 0, i.e. "no timestamp is available"; a time
+    /// far enough in the future to overflow the header's 32-bit field is
+    /// saturated to `u32::MAX` rather than silently wrapping.
+    pub fn mtime_as_datetime(mut self, mtime: SystemTime) -> GzBuilder {
+        self.mtime = mtime
+            .duration_since(UNIX_EPOCH)
+            .map(|d| cmp::min(d.as_secs(), u32::MAX as u64) as u32)
+            .unwrap_or(0);
+        self
+    }
+
+    /// Configure the `operating_system` field in the header.
+    pub fn operating_system(mut self, os: OperatingSystem) -> GzBuilder {
+        self.operating_system = Some(os);
+        self
+    }
+
+    /// Set the FTEXT flag in the header, indicating that the uncompressed
+    /// data is ASCII text.
+    pub fn text(mut self, is_text: bool) -> GzBuilder {
+        self.is_text = is_text;
+        self
+    }
+
+    /// Names the file in this archive.
+    pub fn filename<T: Into<Vec<u8>>>(mut self, filename: T) -> GzBuilder {
+        let filename = filename.into();
+        self.filename = Some(CString::new(filename).unwrap());
+        self
+    }
+
+    /// Set the comment field in the header.
+    pub fn comment<T: Into<Vec<u8>>>(mut self, comment: T) -> GzBuilder {
+        let comment = comment.into();
+        self.comment = Some(CString::new(comment).unwrap());
+        self
+    }
+
+    /// Set the extra data field in the header.
+    pub fn extra<T: Into<Vec<u8>>>(mut self, extra: T) -> GzBuilder {
+        self.extra = Some(extra.into());
+        self
+    }
+
+    fn into_header(self, lvl: Compression) -> Vec<u8> {
+        let GzBuilder {
+            extra,
+            filename,
+            comment,
+            operating_system,
+            is_text,
+            mtime,
+        } = self;
+        let mut flg = 0;
+        let mut header = vec![0u8; 10];
+        if is_text {
+            flg |= FTEXT;
+        }
+        if let Some(v) = extra {
+            flg |= FEXTRA;
+            header.push((v.len() >> 0) as u8);
+            header.push((v.len() >> 8) as u8);
+            header.extend(v);
+        }
+        if let Some(filename) = filename {
+            flg |= FNAME;
+            header.extend(filename.as_bytes_with_nul().iter().cloned());
+        }
+        if let Some(comment) = comment {
+            flg |= FCOMMENT;
+            header.extend(comment.as_bytes_with_nul().iter().cloned());
+        }
+        header[0] = 0x1f;
+        header[1] = 0x8b;
+        header[2] = 8;
+        header[3] = flg;
+        header[4] = (mtime >> 0) as u8;
+        header[5] = (mtime >> 8) as u8;
+        header[6] = (mtime >> 16) as u8;
+        header[7] = (mtime >> 24) as u8;
+        header[8] = if lvl.0 >= Compression::best().0 {
+            2
+        } else if lvl.0 <= Compression::fast().0 {
+            4
+        } else {
+            0
+        };
+        header[9] = operating_system.map(OperatingSystem::to_u8).unwrap_or(255);
+        header
+    }
+
+    /// Consume this builder, creating a reader encoder.
+    ///
+    /// The data read from the returned encoder will be the compressed
+    /// version of the data read from the given reader.
+    pub fn buf_read<R: BufRead>(self, r: R, lvl: Compression) -> bufread::GzEncoder<R> {
+        bufread::gz_encoder(self.into_header(lvl), r, lvl)
+    }
+}